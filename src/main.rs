@@ -2,11 +2,18 @@
 
 //! Take a snapshot of a given binary
 
-use clap::{Parser, ValueEnum};
+mod archive;
+
+use archive::Codec;
+use clap::{Parser, Subcommand, ValueEnum};
 
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
+/// Prefix used for every named volume this tool creates, so volume management
+/// subcommands only ever touch volumes that belong to us.
+const VOLUME_PREFIX: &str = "binonly_snap_";
+
 const DOCKERFILE: &str = r#"
 ###################################################
 #### Ubuntu root FS
@@ -17,9 +24,10 @@ RUN apt-get update -q \
   && rm -rf /var/lib/apt/lists/*
 
 # Copy binary into the root
-COPY $BINARY$ /opt/
+$COPY_OR_BUILD$
 $TRUNCATE$
 $FILES$
+$DRIVER$
 
 ###################################################
 FROM ctfhacker/snapchange_snapshot
@@ -37,6 +45,25 @@ const FUZZER_RS: &str = include_str!("../files/src/fuzzer.rs");
 const LIBFUZZER_RS: &str = include_str!("../files/src/fuzzer.rs.libfuzzer");
 const CONSTANTS_RS: &str = include_str!("../files/src/constants.rs");
 
+/// A minimal libfuzzer driver, linked against a target that exports
+/// `$ENTRYPOINT_SYMBOL$` (and optionally `LLVMFuzzerInitialize`) but doesn't link
+/// libFuzzer's own `main`, giving us a runnable ELF to snapshot.
+const DRIVER_C: &str = r#"#include <stddef.h>
+
+extern int $ENTRYPOINT_SYMBOL$(const unsigned char *data, size_t size);
+extern int LLVMFuzzerInitialize(int *argc, char ***argv) __attribute__((weak));
+
+int main(int argc, char **argv) {
+    static unsigned char buf[$DRIVER_INPUT_SIZE$];
+
+    if (LLVMFuzzerInitialize) {
+        LLVMFuzzerInitialize(&argc, &argv);
+    }
+
+    return $ENTRYPOINT_SYMBOL$(buf, sizeof(buf));
+}
+"#;
+
 /// The type of images available to take a snapshot with
 #[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
 enum ImgType {
@@ -47,21 +74,79 @@ enum ImgType {
     Initramfs,
 }
 
+/// The cargo target to build when `binary` is a Cargo project directory instead of
+/// a prebuilt ELF
+#[derive(Debug, Clone)]
+enum CompiledTarget {
+    /// Build the crate's library and snapshot the resulting staticlib
+    Lib,
+
+    /// Build and snapshot the named `[[bin]]`
+    Bin(String),
+
+    /// Build (but don't run) the named integration test and snapshot its executable
+    Test(String),
+}
+
+/// Management subcommands, alongside the default action of taking a snapshot
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// List the named volumes this tool has created
+    ListVolumes,
+
+    /// Remove a single named volume created for `<binary_name>`
+    RemoveVolume {
+        /// The binary name the volume was created for; the volume removed is
+        /// `binonly_snap_<binary_name>`
+        binary_name: String,
+    },
+
+    /// Remove every named volume this tool has created
+    PruneVolumes,
+
+    /// Safely extract a snapshot archive produced by `--compress`
+    Restore {
+        /// The `snapchange_<name>.tar.<ext>` archive to extract
+        archive: PathBuf,
+
+        /// The directory to extract the archive into. Defaults to the archive's
+        /// name with the `.tar.<ext>` suffix stripped.
+        dest: Option<PathBuf>,
+    },
+}
+
 /// Replay a given snapshot in KVM
 #[derive(Parser, Debug)]
 pub struct CommandLineArgs {
-    /// The function to break and take a snapshot at
+    /// Manage the named volumes used to talk to a remote Docker/Podman engine, or
+    /// restore a snapshot archive
+    #[clap(subcommand)]
+    command: Option<Action>,
+
+    /// The function to break and take a snapshot at. Repeatable: the harness tries
+    /// each candidate breakpoint in the order given
     #[clap(long, short)]
-    pub function: Option<String>,
+    pub function: Vec<String>,
 
     /// The type of image to use to take the snapshot
     #[clap(long)]
     image_type: Option<ImgType>,
 
-    /// This binary is a libfuzzer binary and take a snapshot at `LLVMFuzzerTestOneInput`
+    /// This binary is a libfuzzer binary and take a snapshot at `--entrypoint-symbol`
     #[clap(long, default_value_t = false)]
     pub libfuzzer: bool,
 
+    /// The harness entry symbol to snapshot at when `--libfuzzer` is set, and to build
+    /// a driver around when `--synthesize-driver` is set
+    #[clap(long, default_value = "LLVMFuzzerTestOneInput")]
+    pub entrypoint_symbol: String,
+
+    /// The target doesn't link libFuzzer's own `main`, so compile and link a small C
+    /// driver that calls `--entrypoint-symbol` (and `LLVMFuzzerInitialize`, if present)
+    /// against the target object/archive, producing a runnable entrypoint to snapshot
+    #[clap(long, default_value_t = false)]
+    pub synthesize_driver: bool,
+
     /// Additional packages to install into the base image of the target
     #[clap(long)]
     pub packages: Option<Vec<String>>,
@@ -70,20 +155,335 @@ pub struct CommandLineArgs {
     #[clap(long, value_parser = parse_size)]
     pub input_file_size: Option<u64>,
 
-    /// The binary to take a snapshot of
-    pub binary: PathBuf,
+    /// Transfer the snapshot through a named Docker volume instead of a bind mount.
+    ///
+    /// Required when talking to a remote Docker/Podman engine, since the host path
+    /// backing a bind mount doesn't exist on the daemon's machine. Implied by
+    /// `DOCKER_HOST` being set.
+    #[clap(long, default_value_t = false)]
+    pub remote: bool,
+
+    /// Package the finished snapshot directory into a compressed `.tar.<ext>` archive
+    #[clap(long)]
+    pub compress: Option<Codec>,
+
+    /// The compression level to use for `--compress` (codec-specific range)
+    #[clap(long, default_value_t = 6)]
+    pub compression_level: u32,
+
+    /// The number of prior `snapchange_<name>.old*` directories to retain when the
+    /// output directory already exists. Oldest directories (by modification time)
+    /// are deleted first once this is exceeded.
+    #[clap(long, default_value_t = 8)]
+    pub keep_old: usize,
+
+    /// Delete the existing output directory in place instead of archiving it aside
+    #[clap(long, default_value_t = false)]
+    pub overwrite: bool,
+
+    /// Build `binary` as a Cargo project and snapshot the resulting `[[bin]]`, rather
+    /// than treating `binary` as a prebuilt ELF
+    #[clap(long)]
+    pub bin: Option<String>,
+
+    /// Build `binary` as a Cargo project and snapshot the named integration test,
+    /// rather than treating `binary` as a prebuilt ELF
+    #[clap(long)]
+    pub test: Option<String>,
+
+    /// Build `binary` as a Cargo project and snapshot the resulting staticlib, rather
+    /// than treating `binary` as a prebuilt ELF
+    #[clap(long, default_value_t = false)]
+    pub lib: bool,
+
+    /// The binary to take a snapshot of, or a Cargo project directory when one of
+    /// `--bin`/`--test`/`--lib` is given
+    pub binary: Option<PathBuf>,
 
     /// Optional arguments passed to the binary to snapshot. @@ to use the default input file.
     pub arguments: Option<String>,
 }
 
+/// Run a `docker` command, returning an error if it exits unsuccessfully
+fn docker(args: &[&str]) -> Result<std::process::Output, std::io::Error> {
+    let output = Command::new("docker").args(args).output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "`docker {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(output)
+}
+
+/// Names of the volumes this tool has created, found via the `VOLUME_PREFIX`
+fn our_volumes() -> Result<Vec<String>, std::io::Error> {
+    let output = docker(&[
+        "volume",
+        "ls",
+        "--filter",
+        &format!("name={VOLUME_PREFIX}"),
+        "--format",
+        "{{.Name}}",
+    ])?;
+    // Docker's `name` filter matches anywhere in the name, so keep only true prefix matches
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|name| name.starts_with(VOLUME_PREFIX))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Handle the volume management and `restore` subcommands
+fn run_command(command: Action) -> Result<(), std::io::Error> {
+    match command {
+        Action::ListVolumes => {
+            for volume in our_volumes()? {
+                println!("{volume}");
+            }
+        }
+        Action::RemoveVolume { binary_name } => {
+            let volume = format!("{VOLUME_PREFIX}{binary_name}");
+            docker(&["volume", "rm", &volume])?;
+            println!("Removed volume {volume}");
+        }
+        Action::PruneVolumes => {
+            for volume in our_volumes()? {
+                docker(&["volume", "rm", &volume])?;
+                println!("Removed volume {volume}");
+            }
+        }
+        Action::Restore { archive, dest } => {
+            let dest = dest.unwrap_or_else(|| {
+                let name = archive.to_str().unwrap();
+                for suffix in [".tar.gz", ".tar.bz2", ".tar.xz", ".tar"] {
+                    if let Some(stripped) = name.strip_suffix(suffix) {
+                        return PathBuf::from(stripped);
+                    }
+                }
+                archive.clone()
+            });
+
+            archive::restore_archive(&archive, &dest)?;
+            println!("Restored {} to {}", archive.display(), dest.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy the contents of `/snapshot/` out of a container that was run against a named
+/// volume, since `docker cp` (unlike a bind mount) needs a live or stopped container
+/// to copy from rather than the volume directly.
+fn run_via_volume(docker_tag: &str, binary_name: &str, outdir: &Path) -> Result<(), std::io::Error> {
+    let volume = format!("{VOLUME_PREFIX}{binary_name}");
+    docker(&["volume", "create", &volume])?;
+
+    // Create (but don't start) the container so we keep its ID around for `docker cp`.
+    // We can't use `--rm` here since the container has to survive long enough to copy
+    // the snapshot back out of it.
+    let output = docker(&[
+        "create",
+        "-i",
+        "-v",
+        &format!("{volume}:/snapshot/"),
+        docker_tag,
+    ])?;
+    let container_id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    let mut run_cmd = Command::new("docker")
+        .args(["start", "-a", &container_id])
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    run_cmd.wait()?;
+
+    let snapshot_dir = outdir.join("snapshot");
+    std::fs::create_dir_all(&snapshot_dir)?;
+    docker(&[
+        "cp",
+        &format!("{container_id}:/snapshot/."),
+        snapshot_dir.to_str().unwrap(),
+    ])?;
+
+    docker(&["rm", &container_id])?;
+
+    Ok(())
+}
+
+/// Make way for a fresh `outdir`, either by deleting it in place (`overwrite`) or by
+/// moving it aside to `outdir.old`/`outdir.old1`/... and pruning the oldest such
+/// directories (by modification time) so at most `keep_old` are retained afterwards.
+fn rotate_output_dir(outdir: &str, keep_old: usize, overwrite: bool) -> Result<(), std::io::Error> {
+    if !Path::new(outdir).exists() {
+        return Ok(());
+    }
+
+    if overwrite || keep_old == 0 {
+        return std::fs::remove_dir_all(outdir);
+    }
+
+    let outdir_path = Path::new(outdir);
+    let parent = outdir_path.parent().filter(|p| !p.as_os_str().is_empty());
+    let parent = parent.unwrap_or_else(|| Path::new("."));
+    let base_name = outdir_path.file_name().unwrap().to_str().unwrap();
+    let old_prefix = format!("{base_name}.old");
+
+    let mut old_dirs = Vec::new();
+    for entry in std::fs::read_dir(parent)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(suffix) = name.to_str().and_then(|n| n.strip_prefix(&old_prefix)) else {
+            continue;
+        };
+        if suffix.is_empty() || suffix.parse::<u32>().is_ok() {
+            old_dirs.push((entry.metadata()?.modified()?, entry.path()));
+        }
+    }
+    old_dirs.sort_by_key(|(modified, _)| *modified);
+
+    // `outdir` is about to become one more `.old` directory, so prune down to
+    // `keep_old - 1` existing ones before moving it aside.
+    let keep_existing = keep_old.saturating_sub(1);
+    if old_dirs.len() > keep_existing {
+        for (_, path) in &old_dirs[..old_dirs.len() - keep_existing] {
+            std::fs::remove_dir_all(path)?;
+        }
+    }
+
+    let new_dir = if !Path::new(&old_prefix).exists() {
+        old_prefix
+    } else {
+        let mut count = 1;
+        loop {
+            let candidate = format!("{old_prefix}{count}");
+            if !Path::new(&candidate).exists() {
+                break candidate;
+            }
+            count += 1;
+        }
+    };
+
+    std::fs::rename(outdir, new_dir)
+}
+
+/// Dockerfile steps that compile `DRIVER_C` and link it against the target
+/// object/archive at `/opt/<binary_name>`, replacing it in place with the
+/// resulting runnable ELF.
+fn synthesize_driver_block(binary_name: &str, entrypoint_symbol: &str, input_size: u64) -> String {
+    let driver_c = DRIVER_C
+        .replace("$ENTRYPOINT_SYMBOL$", entrypoint_symbol)
+        .replace("$DRIVER_INPUT_SIZE$", &input_size.to_string());
+
+    // Passed as the *format* string to `printf`, so literal `%` has to be escaped,
+    // newlines are spelled out so the whole source survives as one RUN line, and
+    // single quotes are closed/reopened so a quote in `entrypoint_symbol` can't break
+    // out of the single-quoted shell argument and inject commands.
+    let escaped = driver_c
+        .replace('%', "%%")
+        .replace('\n', "\\n")
+        .replace('\'', "'\\''");
+
+    format!(
+        "RUN printf '{escaped}' > /opt/driver.c\n\
+         RUN clang -xc /opt/driver.c -x none /opt/{binary_name} -o /opt/{binary_name}.synthesized \\\n    \
+         && mv /opt/{binary_name}.synthesized /opt/{binary_name}"
+    )
+}
+
+/// Which cargo target (if any) `--bin`/`--test`/`--lib` selected. `--bin` wins if
+/// more than one is given.
+fn compiled_target(bin: &Option<String>, test: &Option<String>, lib: bool) -> Option<CompiledTarget> {
+    if let Some(name) = bin {
+        Some(CompiledTarget::Bin(name.clone()))
+    } else if let Some(name) = test {
+        Some(CompiledTarget::Test(name.clone()))
+    } else if lib {
+        Some(CompiledTarget::Lib)
+    } else {
+        None
+    }
+}
+
+/// Read the `name` out of a Cargo project's `[package]` table, for locating the
+/// staticlib a `--lib` build produces (`lib<name>.a`)
+fn cargo_package_name(project_dir: &Path) -> Result<String, std::io::Error> {
+    let cargo_toml = std::fs::read_to_string(project_dir.join("Cargo.toml"))?;
+
+    let mut in_package_table = false;
+    for line in cargo_toml.lines() {
+        let line = line.trim();
+        if let Some(table) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_package_table = table == "package";
+            continue;
+        }
+
+        if in_package_table {
+            if let Some(value) = line.strip_prefix("name") {
+                if let Some(value) = value.trim_start().strip_prefix('=') {
+                    let name = value.trim().trim_matches('"');
+                    return Ok(name.to_string());
+                }
+            }
+        }
+    }
+
+    Err(std::io::Error::other(format!(
+        "could not find `name` under [package] in {:?}",
+        project_dir.join("Cargo.toml")
+    )))
+}
+
+/// Dockerfile steps to `COPY` a Cargo project in, build the selected target, and
+/// `cp` the resulting artifact to `/opt/<binary_name>` for snapshotting.
+fn build_project_block(
+    project_dir: &Path,
+    binary_name: &str,
+    target: &CompiledTarget,
+) -> Result<String, std::io::Error> {
+    let project = project_dir.to_str().unwrap();
+
+    let build_and_copy = match target {
+        CompiledTarget::Bin(name) => format!(
+            "cargo build --bin {name} && cp target/debug/{name} /opt/{binary_name}"
+        ),
+        CompiledTarget::Test(name) => format!(
+            "cargo test --test {name} --no-run --message-format=json > /tmp/cargo_test.json \\\n    \
+             && TEST_BIN=$(grep -o '\"executable\":\"[^\"]*\"' /tmp/cargo_test.json \\\n        \
+             | sed -e 's/^\"executable\":\"//' -e 's/\"$//' | tail -n1) \\\n    \
+             && cp \"$TEST_BIN\" /opt/{binary_name}"
+        ),
+        CompiledTarget::Lib => {
+            let crate_name = cargo_package_name(project_dir)?.replace('-', "_");
+            format!("cargo build --lib && cp target/debug/lib{crate_name}.a /opt/{binary_name}")
+        }
+    };
+
+    Ok(format!(
+        "COPY {project:?} /build/\nRUN cd /build && {build_and_copy}"
+    ))
+}
+
 fn main() -> Result<(), std::io::Error> {
     const TRUNCATE_FILE_NAME: &str = "/opt/truncated_input_file";
 
     let args = CommandLineArgs::parse();
 
+    if let Some(command) = args.command {
+        return run_command(command);
+    }
+
+    let remote = args.remote || std::env::var_os("DOCKER_HOST").is_some();
+
+    let binary = args.binary.ok_or_else(|| {
+        std::io::Error::other(
+            "BINARY is required when not running a subcommand",
+        )
+    })?;
+
     // let binary = std::path::absolute(args.binary)?;
-    let binary_name = args.binary.as_path().file_name().unwrap().to_str().unwrap();
+    let binary_name = binary.as_path().file_name().unwrap().to_str().unwrap();
 
     // Flag for if there is an input file as an argument
     let mut has_file = false;
@@ -121,26 +521,49 @@ fn main() -> Result<(), std::io::Error> {
         "".to_string()
     };
 
+    let driver = if args.synthesize_driver {
+        let input_size = args.input_file_size.unwrap_or(32 * 1024);
+        synthesize_driver_block(binary_name, &args.entrypoint_symbol, input_size)
+    } else {
+        "".to_string()
+    };
+
+    let build_target = compiled_target(&args.bin, &args.test, args.lib);
+    if build_target.is_some() && !binary.join("Cargo.toml").is_file() {
+        return Err(std::io::Error::other(format!(
+            "--bin/--test/--lib requires BINARY to be a Cargo project directory, but {binary:?} has no Cargo.toml"
+        )));
+    }
+
+    let mut packages = args.packages.unwrap_or_else(Vec::new);
+    let copy_or_build = if let Some(target) = &build_target {
+        packages.push("cargo".to_string());
+        build_project_block(&binary, binary_name, target)?
+    } else {
+        format!("COPY {} /opt/", binary.to_str().unwrap())
+    };
+
     let mut dockerfile = DOCKERFILE
         .to_string()
-        .replace("$BINARY$", args.binary.to_str().unwrap())
         .replace("$BINARYNAME$", binary_name)
-        .replace(
-            "$PACKAGES$",
-            &args.packages.unwrap_or_else(|| Vec::new()).join(" "),
-        )
+        .replace("$PACKAGES$", &packages.join(" "))
         .replace("$TRUNCATE$", &truncate)
-        .replace("$FILES$", &files.join("\n"));
+        .replace("$FILES$", &files.join("\n"))
+        .replace("$DRIVER$", &driver)
+        .replace("$COPY_OR_BUILD$", &copy_or_build);
 
     // Default to taking a snapshot at `main`
-    let function = args.function.unwrap_or_else(|| {
-        if args.libfuzzer {
-            "LLVMFuzzerTestOneInput".to_string()
-        } else {
-            "main".to_string()
-        }
-    });
-    dockerfile.push_str(&format!("ENV SNAPSHOT_FUNCTION={}\n", &function));
+    let functions = if !args.function.is_empty() {
+        args.function.clone()
+    } else if args.libfuzzer {
+        vec![args.entrypoint_symbol.clone()]
+    } else {
+        vec!["main".to_string()]
+    };
+    dockerfile.push_str(&format!(
+        "ENV SNAPSHOT_FUNCTION=\"{}\"\n",
+        functions.join(" ")
+    ));
 
     // Default to using an initramfs image type
     let imgtype = args.image_type.unwrap_or(ImgType::Initramfs);
@@ -173,30 +596,16 @@ fn main() -> Result<(), std::io::Error> {
 
     let outdir = format!("snapchange_{binary_name}");
     let outdir = Path::new(&outdir);
-    let volume = format!(
+    let bind_mount = format!(
         "{}:/snapshot/",
         std::path::absolute(outdir.join("snapshot"))?
             .to_str()
             .unwrap()
     );
 
-    // Move the output directory if it exists already
+    // Make way for the output directory if it exists already
     let outdir = outdir.to_str().unwrap();
-    if Path::new(outdir).exists() {
-        let mut new_dir = format!("{outdir}.old");
-        for count in 1..64 * 1024 {
-            new_dir = format!("{outdir}.old{count}");
-            if !Path::new(&new_dir).exists() {
-                break;
-            }
-        }
-
-        if Path::new(&new_dir).exists() {
-            panic!("Too many old dirs currently.. Cannot move the output directory {outdir}");
-        }
-
-        std::fs::rename(outdir, &new_dir).unwrap();
-    }
+    rotate_output_dir(outdir, args.keep_old, args.overwrite)?;
 
     // Create the output directory
     std::fs::create_dir_all(&outdir)?;
@@ -209,7 +618,7 @@ fn main() -> Result<(), std::io::Error> {
     std::fs::write(outdir.join("reset.sh"), RESET_SH)?;
     std::fs::write(outdir.join("src").join("main.rs"), MAIN_RS)?;
 
-    let fuzzer_file = if function == "LLVMFuzzerTestOneInput" {
+    let fuzzer_file = if args.libfuzzer {
         LIBFUZZER_RS
     } else {
         FUZZER_RS
@@ -218,16 +627,30 @@ fn main() -> Result<(), std::io::Error> {
     std::fs::write(outdir.join("src").join("fuzzer.rs"), fuzzer_file)?;
     std::fs::write(outdir.join("src").join("constants.rs"), CONSTANTS_RS)?;
 
-    // docker run -i \
-    //     -v $(realpath -m ./snapshot):/snapshot/ \
-    //     harness6
-    let mut run_cmd = Command::new("docker")
-        .args(["run", "-i", "-v", &volume, &docker_tag])
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()?;
+    if remote {
+        // The host path backing a bind mount doesn't exist on a remote daemon's
+        // machine, so ferry the snapshot through a named volume and `docker cp`
+        // it back out instead.
+        run_via_volume(&docker_tag, binary_name, outdir)?;
+    } else {
+        // docker run -i \
+        //     -v $(realpath -m ./snapshot):/snapshot/ \
+        //     harness6
+        let mut run_cmd = Command::new("docker")
+            .args(["run", "-i", "-v", &bind_mount, &docker_tag])
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        run_cmd.wait().unwrap();
+    }
 
-    run_cmd.wait().unwrap();
+    if let Some(codec) = args.compress {
+        if codec != Codec::None {
+            let archive_path = archive::compress_dir(outdir, codec, args.compression_level)?;
+            println!("Wrote {}", archive_path.display());
+        }
+    }
 
     Ok(())
 }